@@ -1,5 +1,16 @@
+//! This module relies on the `ptr_metadata` and `unsize` nightly features
+//! (`Pointee`/`DynMetadata`/`ptr::from_raw_parts[_mut]` and the `Unsize` bound used by
+//! `MetaTable::register_type`/`upcast`). The crate root must enable them with
+//! `#![feature(ptr_metadata, unsize)]`; this file cannot do so on its own, since a crate's
+//! `#![feature(...)]` attributes only take effect at the crate root. Building with a stable
+//! toolchain will fail with `E0658`, and enabling the features on stable itself fails with
+//! `E0554`, so this module currently requires a nightly `rustc` — confirm that's the intended
+//! MSRV/toolchain story for this crate before relying on it.
+
 use std::any::TypeId;
-use std::marker::PhantomData;
+use std::cell::RefCell;
+use std::marker::{PhantomData, Unsize};
+use std::ptr::{self, DynMetadata, Pointee};
 
 use fxhash::FxHashMap;
 use mopa::Any;
@@ -41,8 +52,12 @@ pub trait CastFrom<T> {
 }
 
 /// An iterator for the `MetaTable`.
-pub struct MetaIter<'a, T: ?Sized + 'a> {
-    fat: &'a [Fat],
+pub struct MetaIter<'a, T>
+where
+    T: ?Sized + Pointee<Metadata = DynMetadata<T>> + 'a,
+{
+    meta: &'a [DynMetadata<T>],
+    active: &'a [usize],
     index: usize,
     res: &'a mut Resources,
     tys: &'a [TypeId],
@@ -52,59 +67,38 @@ pub struct MetaIter<'a, T: ?Sized + 'a> {
 
 impl<'a, T> Iterator for MetaIter<'a, T>
 where
-    T: ?Sized + 'a,
+    T: ?Sized + Pointee<Metadata = DynMetadata<T>> + 'a,
 {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         use std::mem::transmute;
 
-        let index = self.index;
-        self.index += 1;
-
         // Ugly hack that works due to `UnsafeCell` and distinct resources.
-        unsafe {
-            transmute::<&mut Resources, &'a mut Resources>(&mut self.res)
-                .get_mut_raw(match self.tys.get(index) {
-                    Some(&x) => x,
-                    None => return None,
-                })
-                .map(|res| self.fat[index].create_ptr::<T>(&*res as *const _ as *const ()))
-                .map(|ptr| &*ptr)
-                .or_else(|| self.next())
-        }
-    }
-}
-
-struct Fat(usize);
+        let res = unsafe { transmute::<&mut Resources, &'a mut Resources>(&mut self.res) };
 
-impl Fat {
-    pub unsafe fn from_ptr<T: ?Sized>(t: &T) -> Self {
-        use std::ptr::read;
+        loop {
+            let &ind = self.active.get(self.index)?;
+            self.index += 1;
 
-        assert_unsized::<T>();
+            if let Some(r) = res.get_mut_raw(self.tys[ind]) {
+                let ptr = unsafe {
+                    ptr::from_raw_parts::<T>(&*r as *const _ as *const (), self.meta[ind])
+                };
 
-        let fat_ptr = &t as *const &T as *const usize;
-        // Memory layout:
-        // [object pointer, vtable pointer]
-        //  ^^^^^^^^^^^^^^  ^^^^^^^^^^^^^^
-        //  8 bytes       | 8 bytes
-        // (on 32-bit both have 4 bytes)
-        let vtable = read::<usize>(fat_ptr.offset(1));
-
-        Fat(vtable)
-    }
-
-    pub unsafe fn create_ptr<T: ?Sized>(&self, ptr: *const ()) -> *const T {
-        let fat_ptr: (*const (), usize) = (ptr, self.0);
-
-        *(&fat_ptr as *const (*const (), usize) as *const *const T)
+                return Some(unsafe { &*ptr });
+            }
+        }
     }
 }
 
 /// A mutable iterator for the `MetaTable`.
-pub struct MetaIterMut<'a, T: ?Sized + 'a> {
-    fat: &'a [Fat],
+pub struct MetaIterMut<'a, T>
+where
+    T: ?Sized + Pointee<Metadata = DynMetadata<T>> + 'a,
+{
+    meta: &'a [DynMetadata<T>],
+    active: &'a [usize],
     index: usize,
     res: &'a mut Resources,
     tys: &'a [TypeId],
@@ -114,26 +108,25 @@ pub struct MetaIterMut<'a, T: ?Sized + 'a> {
 
 impl<'a, T> Iterator for MetaIterMut<'a, T>
 where
-    T: ?Sized + 'a,
+    T: ?Sized + Pointee<Metadata = DynMetadata<T>> + 'a,
 {
     type Item = &'a mut T;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         use std::mem::transmute;
 
-        let index = self.index;
-        self.index += 1;
-
         // Ugly hack that works due to `UnsafeCell` and distinct resources.
-        unsafe {
-            transmute::<&mut Resources, &'a mut Resources>(&mut self.res)
-                .get_mut_raw(match self.tys.get(index) {
-                    Some(&x) => x,
-                    None => return None,
-                })
-                .map(|res| self.fat[index].create_ptr::<T>(res as *mut _ as *const ()) as *mut T)
-                .map(|ptr| &mut *ptr)
-                .or_else(|| self.next())
+        let res = unsafe { transmute::<&mut Resources, &'a mut Resources>(&mut self.res) };
+
+        loop {
+            let &ind = self.active.get(self.index)?;
+            self.index += 1;
+
+            if let Some(r) = res.get_mut_raw(self.tys[ind]) {
+                let ptr = ptr::from_raw_parts_mut::<T>(r as *mut _ as *mut (), self.meta[ind]);
+
+                return Some(unsafe { &mut *ptr });
+            }
         }
     }
 }
@@ -207,19 +200,27 @@ where
 ///     assert_eq!(iter.next().unwrap().method1(), 1);
 /// }
 /// ```
-pub struct MetaTable<T: ?Sized> {
-    fat: Vec<Fat>,
+pub struct MetaTable<T>
+where
+    T: ?Sized + Pointee<Metadata = DynMetadata<T>>,
+{
+    meta: Vec<DynMetadata<T>>,
     indices: FxHashMap<TypeId, usize>,
     tys: Vec<TypeId>,
+    // Memoized indices (into `meta` / `tys`) of the registered types that are currently present
+    // in the `Resources` passed to the `iter`/`iter_mut` call that built it. `None` means the
+    // cache needs to be rebuilt.
+    active: RefCell<Option<Vec<usize>>>,
     // `MetaTable` is invariant over `T`
     marker: PhantomData<*mut T>,
 }
 
-impl<T: ?Sized> MetaTable<T> {
+impl<T> MetaTable<T>
+where
+    T: ?Sized + Pointee<Metadata = DynMetadata<T>>,
+{
     /// Creates a new `MetaTable`.
     pub fn new() -> Self {
-        assert_unsized::<T>();
-
         Default::default()
     }
 
@@ -231,9 +232,31 @@ impl<T: ?Sized> MetaTable<T> {
         R: Resource,
         T: CastFrom<R> + 'static,
     {
-        use std::collections::hash_map::Entry;
+        let meta = ptr::metadata(<T as CastFrom<R>>::cast(r) as *const T);
 
-        let fat = unsafe { Fat::from_ptr(<T as CastFrom<R>>::cast(r)) };
+        self.insert::<R>(meta);
+    }
+
+    /// Registers the resource type `R` that implements the trait `T`, without needing an
+    /// instance of `R` to harvest the vtable from.
+    /// This is useful if `R` is expensive or impossible to construct just for registration.
+    pub fn register_type<R>(&mut self)
+    where
+        R: Resource + Unsize<T>,
+        T: 'static,
+    {
+        // The unsizing coercion happens entirely on the raw pointer, so the null data pointer
+        // is never read, nor is a `&R` ever materialized (which would be UB for a null pointer).
+        let meta = ptr::metadata(ptr::null::<R>() as *const T);
+
+        self.insert::<R>(meta);
+    }
+
+    fn insert<R>(&mut self, meta: DynMetadata<T>)
+    where
+        R: Resource,
+    {
+        use std::collections::hash_map::Entry;
 
         let ty_id = TypeId::of::<R>();
 
@@ -243,15 +266,57 @@ impl<T: ?Sized> MetaTable<T> {
             Entry::Occupied(occ) => {
                 let ind = *occ.get();
 
-                self.fat[ind] = fat;
+                self.meta[ind] = meta;
             }
             Entry::Vacant(vac) => {
                 vac.insert(len);
 
-                self.fat.push(fat);
+                self.meta.push(meta);
                 self.tys.push(ty_id);
             }
         }
+
+        self.invalidate_cache();
+    }
+
+    /// Invalidates the memoized set of active indices used by `iter`/`iter_mut`.
+    /// `register`/`register_type` already call this; use it yourself if you mutate `Resources`'
+    /// membership (insert or remove resources) between iterations.
+    ///
+    /// This takes `&mut self`, not `&self`: the cache is handed out to `MetaIter`/`MetaIterMut`
+    /// as a borrow tied to the table's own lifetime, so the borrow checker must be the one
+    /// enforcing that it's never invalidated while such an iterator is still alive. A `&self`
+    /// method here would type-check a call that frees the iterator's backing storage out from
+    /// under it.
+    pub fn invalidate_cache(&mut self) {
+        *self.active.get_mut() = None;
+    }
+
+    /// Returns the indices (into `meta`/`tys`) of the registered types that currently have a
+    /// resource present in `res`, computing and memoizing them on the first call after
+    /// registration or an explicit `invalidate_cache`.
+    fn active_indices<'a>(&'a self, res: &mut Resources) -> &'a [usize] {
+        if self.active.borrow().is_none() {
+            let active = self
+                .tys
+                .iter()
+                .enumerate()
+                .filter(|&(_, &ty)| res.get_mut_raw(ty).is_some())
+                .map(|(ind, _)| ind)
+                .collect();
+
+            *self.active.borrow_mut() = Some(active);
+        }
+
+        // Ugly hack that works due to `UnsafeCell` and distinct resources: `self` is borrowed
+        // for `'a` already, and `invalidate_cache`/`register`/`register_type` all require
+        // `&mut self`, so the borrow checker guarantees the cache can't be cleared while this
+        // borrow (or an iterator built from it) is alive.
+        unsafe {
+            use std::mem::transmute;
+
+            transmute::<&[usize], &'a [usize]>(self.active.borrow().as_ref().unwrap())
+        }
     }
 
     /// Tries to convert `res` to a trait object of type `&T`.
@@ -259,9 +324,9 @@ impl<T: ?Sized> MetaTable<T> {
     /// this will return `None`.
     pub fn get<'a>(&self, res: &'a Resource) -> Option<&'a T> {
         unsafe {
-            self.indices
-                .get(&Any::get_type_id(res))
-                .map(move |&ind| &*self.fat[ind].create_ptr(res as *const _ as *const ()))
+            self.indices.get(&Any::get_type_id(res)).map(move |&ind| {
+                &*ptr::from_raw_parts::<T>(res as *const _ as *const (), self.meta[ind])
+            })
         }
     }
 
@@ -271,15 +336,43 @@ impl<T: ?Sized> MetaTable<T> {
     pub fn get_mut<'a>(&self, res: &'a mut Resource) -> Option<&'a mut T> {
         unsafe {
             self.indices.get(&Any::get_type_id(res)).map(move |&ind| {
-                &mut *(self.fat[ind].create_ptr::<T>(res as *const _ as *const ()) as *mut T)
+                &mut *ptr::from_raw_parts_mut::<T>(res as *mut _ as *mut (), self.meta[ind])
             })
         }
     }
 
+    /// Returns the size in bytes of the concrete resource behind `res`, as recorded when it was
+    /// registered. Returns `None` if `res` wasn't registered for `T`.
+    pub fn size_of(&self, res: &Resource) -> Option<usize> {
+        self.indices
+            .get(&Any::get_type_id(res))
+            .map(|&ind| self.meta[ind].size_of())
+    }
+
+    /// Returns the alignment in bytes of the concrete resource behind `res`, as recorded when it
+    /// was registered. Returns `None` if `res` wasn't registered for `T`.
+    pub fn align_of(&self, res: &Resource) -> Option<usize> {
+        self.indices
+            .get(&Any::get_type_id(res))
+            .map(|&ind| self.meta[ind].align_of())
+    }
+
     /// Iterates all resources that implement `T` and were registered.
+    ///
+    /// The set of registered types actually present in `res` is memoized the first time this
+    /// (or `iter_mut`) is called after registration or `invalidate_cache`. **This assumes every
+    /// call passes the same, stable `Resources` instance** (which is the normal way a
+    /// `MetaTable` is used, alongside a single `World`/`Resources`). If you ever call `iter`/
+    /// `iter_mut` with a *different* `Resources` than the one the cache was built against, or
+    /// otherwise insert/remove resources in `res` between calls, you must
+    /// call `invalidate_cache()` first — otherwise stale presence information from the old
+    /// `Resources` is silently reused and resources that are actually present can be skipped.
     pub fn iter<'a>(&'a self, res: &'a mut Resources) -> MetaIter<'a, T> {
+        let active = self.active_indices(res);
+
         MetaIter {
-            fat: &self.fat,
+            meta: &self.meta,
+            active,
             index: 0,
             res,
             tys: &self.tys,
@@ -288,37 +381,66 @@ impl<T: ?Sized> MetaTable<T> {
     }
 
     /// Iterates all resources that implement `T` and were registered mutably.
+    ///
+    /// See the caching caveat on [`iter`](MetaTable::iter): this assumes `res` is always the
+    /// same `Resources` instance between calls, and requires `invalidate_cache()` otherwise.
     pub fn iter_mut<'a>(&'a self, res: &'a mut Resources) -> MetaIterMut<'a, T> {
+        let active = self.active_indices(res);
+
         MetaIterMut {
-            fat: &self.fat,
+            meta: &self.meta,
+            active,
             index: 0,
             res,
             tys: &self.tys,
             marker: PhantomData,
         }
     }
+
+    /// Builds a `MetaTable<Super>` covering the same registered resources as `self`, for a
+    /// supertrait `Super` of `T`. This mirrors trait-upcast coercion: for every registered type
+    /// we reconstruct a dangling `T` trait object from its stored metadata and unsize it to
+    /// `Super`, so callers don't have to maintain a second table and re-register everything.
+    pub fn upcast<Super>(&self) -> MetaTable<Super>
+    where
+        Super: ?Sized + Pointee<Metadata = DynMetadata<Super>>,
+        T: Unsize<Super>,
+    {
+        let meta = self
+            .meta
+            .iter()
+            .map(|&meta| {
+                let dangling = ptr::from_raw_parts::<T>(ptr::null::<()>(), meta);
+
+                ptr::metadata(dangling as *const Super)
+            })
+            .collect();
+
+        MetaTable {
+            meta,
+            indices: self.indices.clone(),
+            tys: self.tys.clone(),
+            active: RefCell::new(None),
+            marker: PhantomData,
+        }
+    }
 }
 
 impl<T> Default for MetaTable<T>
 where
-    T: ?Sized,
+    T: ?Sized + Pointee<Metadata = DynMetadata<T>>,
 {
     fn default() -> Self {
         MetaTable {
-            fat: Default::default(),
+            meta: Default::default(),
             indices: Default::default(),
             tys: Default::default(),
+            active: RefCell::new(None),
             marker: Default::default(),
         }
     }
 }
 
-fn assert_unsized<T: ?Sized>() {
-    use std::mem::size_of;
-
-    assert_eq!(size_of::<&T>(), 2 * size_of::<usize>());
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -394,4 +516,120 @@ mod tests {
             assert_eq!(obj.method1(), 4);
         }
     }
+
+    #[test]
+    fn test_register_type() {
+        let mut res = Resources::new();
+
+        res.insert(ImplementorA(3));
+        res.insert(ImplementorB(1));
+
+        let mut table = MetaTable::<Object>::new();
+        table.register_type::<ImplementorA>();
+        table.register_type::<ImplementorB>();
+
+        let mut iter = table.iter(&mut res);
+        assert_eq!(iter.next().unwrap().method1(), 3);
+        assert_eq!(iter.next().unwrap().method1(), 1);
+    }
+
+    #[test]
+    fn test_size_align() {
+        use std::mem::{align_of, size_of};
+
+        let mut table = MetaTable::<Object>::new();
+        table.register(&ImplementorA(125));
+
+        let a = ImplementorA(3);
+        assert_eq!(table.size_of(&a), Some(size_of::<ImplementorA>()));
+        assert_eq!(table.align_of(&a), Some(align_of::<ImplementorA>()));
+
+        let b = ImplementorB(1);
+        assert_eq!(table.size_of(&b), None);
+        assert_eq!(table.align_of(&b), None);
+    }
+
+    trait Named {
+        fn name(&self) -> &'static str;
+    }
+
+    trait Labeled: Named {
+        fn value(&self) -> i32;
+    }
+
+    impl<T> CastFrom<T> for Named
+    where
+        T: Named + 'static,
+    {
+        fn cast(t: &T) -> &Self {
+            t
+        }
+
+        fn cast_mut(t: &mut T) -> &mut Self {
+            t
+        }
+    }
+
+    impl<T> CastFrom<T> for Labeled
+    where
+        T: Labeled + 'static,
+    {
+        fn cast(t: &T) -> &Self {
+            t
+        }
+
+        fn cast_mut(t: &mut T) -> &mut Self {
+            t
+        }
+    }
+
+    struct Widget(i32);
+
+    impl Named for Widget {
+        fn name(&self) -> &'static str {
+            "widget"
+        }
+    }
+
+    impl Labeled for Widget {
+        fn value(&self) -> i32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_upcast() {
+        let mut res = Resources::new();
+
+        res.insert(Widget(7));
+
+        let mut table = MetaTable::<Labeled>::new();
+        table.register(&Widget(0));
+
+        let named: MetaTable<Named> = table.upcast();
+
+        let mut iter = named.iter(&mut res);
+        assert_eq!(iter.next().unwrap().name(), "widget");
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let mut res = Resources::new();
+
+        let mut table = MetaTable::<Object>::new();
+        table.register(&ImplementorA(125));
+
+        // Nothing registered for `Object` is present in `res` yet, so the cache memoizes an
+        // empty active set.
+        assert!(table.iter(&mut res).next().is_none());
+
+        // Insert the resource behind the table's back; the memoized (empty) active set is
+        // stale now, so a plain `iter` call would still miss it.
+        res.insert(ImplementorA(3));
+        assert!(table.iter(&mut res).next().is_none());
+
+        // After telling the table to recompute, it picks the resource back up.
+        table.invalidate_cache();
+        assert_eq!(table.iter(&mut res).next().unwrap().method1(), 3);
+    }
 }